@@ -1,27 +1,40 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 
 use anyhow::Result;
-use pyo3::{prelude::*, types::PyTuple};
+use pyo3::{exceptions::PyValueError, prelude::*, types::PyTuple};
 use tokio::{
     net::UdpSocket,
     sync::broadcast::{self, Sender as BroadcastSender},
     sync::mpsc::{self, channel, unbounded_channel},
     sync::Notify,
 };
+#[cfg(unix)]
+use tokio::net::UnixListener;
+#[cfg(windows)]
 use tokio::net::windows::named_pipe::{PipeMode, ServerOptions};
+#[cfg(windows)]
 use windows::core::{HSTRING, PCWSTR};
+#[cfg(windows)]
 use windows::w;
+#[cfg(windows)]
 use windows::Win32::UI::Shell::ShellExecuteW;
+#[cfg(windows)]
 use windows::Win32::UI::WindowsAndMessaging::{SW_HIDE, SW_SHOWNORMAL};
 use x25519_dalek::PublicKey;
+#[cfg(windows)]
 use mitmproxy_rs::MAX_PACKET_SIZE;
-use mitmproxy_rs::messages::TransportCommand;
+use mitmproxy_rs::messages::{PeerUpdate, TransportCommand};
 
 use mitmproxy_rs::network::{NetworkTask};
 use mitmproxy_rs::packet_sources::{
-    PacketSourceBuilder, PacketSourceTask, WinDivertBuilder, WireGuardBuilder,
+    PacketSourceBuilder, PacketSourceTask, QuicBuilder, WireGuardBuilder,
 };
+#[cfg(windows)]
+use mitmproxy_rs::packet_sources::WinDivertBuilder;
+#[cfg(unix)]
+use mitmproxy_rs::packet_sources::UnixSocketBuilder;
+use mitmproxy_rs::relay::PacketRelayer;
 use mitmproxy_rs::shutdown::ShutdownTask;
 use crate::task::PyInteropTask;
 use crate::tcp_stream::event_queue_unavailable;
@@ -56,6 +69,13 @@ impl Server {
         Ok(())
     }
 
+    pub fn update_peers(&self, update: PeerUpdate) -> PyResult<()> {
+        let cmd = TransportCommand::UpdatePeers(update);
+
+        self.event_tx.send(cmd).map_err(event_queue_unavailable)?;
+        Ok(())
+    }
+
     pub fn close(&mut self) {
         if !self.closing {
             self.closing = true;
@@ -85,7 +105,13 @@ impl Server {
     ) -> Result<Self> {
         log::debug!("Initializing WireGuard server ...");
 
-        // initialize channels between the WireGuard server and the virtual network device
+        // initialize channels between the packet source and the relayer
+        // - frames arriving from the source are tagged with an opaque source/peer tag so the
+        //   relayer knows which tunnel endpoint they came from
+        let (source_to_relay_tx, source_to_relay_rx) = channel(256);
+        let (relay_to_source_tx, relay_to_source_rx) = channel(256);
+
+        // initialize channels between the relayer and the virtual network device
         let (wg_to_smol_tx, wg_to_smol_rx) = channel(256);
         let (smol_to_wg_tx, smol_to_wg_rx) = channel(256);
 
@@ -102,8 +128,23 @@ impl Server {
         let (sd_trigger, _sd_watcher) = broadcast::channel(1);
         let sd_barrier = Arc::new(Notify::new());
 
-        let wg_task =
-            packet_source_builder.build(wg_to_smol_tx, smol_to_wg_rx, sd_trigger.subscribe());
+        let wg_task = packet_source_builder.build(
+            source_to_relay_tx,
+            relay_to_source_rx,
+            sd_trigger.subscribe(),
+        );
+
+        // the relayer owns the tag -> channel map and decides which packet goes to which
+        // endpoint, decoupling the capture/tunnel side from the virtual network device; this is
+        // what makes it possible to swap the local source channel for a socket to a remote
+        // capture host without touching NetworkTask or PyInteropTask
+        let relay_task = PacketRelayer::new(
+            source_to_relay_rx,
+            relay_to_source_tx,
+            wg_to_smol_tx,
+            smol_to_wg_rx,
+            sd_trigger.subscribe(),
+        );
 
         // initialize virtual network device
         let nw_task = NetworkTask::new(
@@ -132,6 +173,7 @@ impl Server {
 
         // spawn tasks
         let wg_handle = tokio::spawn(async move { wg_task.run().await });
+        let relay_handle = tokio::spawn(async move { relay_task.run().await });
         let net_handle = tokio::spawn(async move { nw_task.run().await });
         let py_handle = tokio::spawn(async move { py_task.run().await });
 
@@ -139,6 +181,7 @@ impl Server {
         let sd_task = ShutdownTask::new(
             py_handle,
             wg_handle,
+            relay_handle,
             net_handle,
             sd_trigger.clone(),
             sd_barrier.clone(),
@@ -162,12 +205,51 @@ impl Drop for Server {
     }
 }
 
+/// Substitute the catch-all `0.0.0.0/0` + `::/0` allowed-IPs when none were given, preserving the
+/// historical single-peer behavior of routing all traffic to that peer.
+fn catch_all_if_empty(allowed_ips: Vec<(IpAddr, u8)>) -> Vec<(IpAddr, u8)> {
+    if allowed_ips.is_empty() {
+        vec![("0.0.0.0".parse().unwrap(), 0), ("::".parse().unwrap(), 0)]
+    } else {
+        allowed_ips
+    }
+}
+
+/// Bind a UDP socket for `host`/`port`, falling back to dual-stack `0.0.0.0` + `::` when `host`
+/// is empty, and log the bound address(es) under the given `proto` label (e.g. `"WireGuard"`,
+/// `"QUIC"`).
+async fn bind_dual_stack(host: &str, port: u16, proto: &str) -> Result<UdpSocket> {
+    let socket_addrs = if host.is_empty() {
+        vec![
+            SocketAddr::new("0.0.0.0".parse().unwrap(), port),
+            SocketAddr::new("::".parse().unwrap(), port),
+        ]
+    } else {
+        vec![SocketAddr::new(host.parse()?, port)]
+    };
+
+    let socket = UdpSocket::bind(socket_addrs.as_slice()).await?;
+
+    log::debug!(
+        "{proto} server listening for UDP connections on {} ...",
+        socket_addrs
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<String>>()
+            .join(" and ")
+    );
+
+    Ok(socket)
+}
+
+#[cfg(windows)]
 #[pyclass]
 #[derive(Debug)]
 pub struct WindowsProxy {
     server: Server,
 }
 
+#[cfg(windows)]
 #[pymethods]
 impl WindowsProxy {
     pub fn send_datagram(
@@ -188,6 +270,7 @@ impl WindowsProxy {
     }
 }
 
+#[cfg(windows)]
 impl WindowsProxy {
     pub async fn init(py_tcp_handler: PyObject, py_udp_handler: PyObject) -> Result<Self> {
         let pipe_name = format!(
@@ -224,6 +307,50 @@ impl WindowsProxy {
     }
 }
 
+#[cfg(unix)]
+#[pyclass]
+#[derive(Debug)]
+pub struct UnixProxy {
+    server: Server,
+}
+
+#[cfg(unix)]
+#[pymethods]
+impl UnixProxy {
+    pub fn send_datagram(
+        &self,
+        data: Vec<u8>,
+        src_addr: &PyTuple,
+        dst_addr: &PyTuple,
+    ) -> PyResult<()> {
+        self.server.send_datagram(data, src_addr, dst_addr)
+    }
+
+    pub fn close(&mut self) {
+        self.server.close()
+    }
+
+    pub fn wait_closed<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        self.server.wait_closed(py)
+    }
+}
+
+#[cfg(unix)]
+impl UnixProxy {
+    pub async fn init(py_tcp_handler: PyObject, py_udp_handler: PyObject) -> Result<Self> {
+        let socket_path = format!("/tmp/mitmproxy-{}.sock", std::process::id());
+        // remove a stale socket file left behind by a previous, uncleanly terminated run
+        let _ = std::fs::remove_file(&socket_path);
+
+        let listener = UnixListener::bind(&socket_path)?;
+
+        let unix_task_builder = UnixSocketBuilder::new(listener, socket_path);
+
+        let server = Server::init(unix_task_builder, py_tcp_handler, py_udp_handler).await?;
+        Ok(UnixProxy { server })
+    }
+}
+
 /// A running WireGuard server.
 ///
 /// A new server can be started by calling the `start_server` coroutine. Its public API is intended
@@ -276,6 +403,49 @@ impl WireGuardServer {
     pub fn __repr__(&self) -> String {
         format!("WireGuardServer({})", self.local_addr)
     }
+
+    /// Add a new WireGuard peer to the already-running server, or reconfigure an existing one
+    /// that shares the same public key, without tearing down the UDP socket or any running tasks.
+    ///
+    /// `allowed_ips` is a list of `(ip, prefix_len)` tuples; an empty list defaults to
+    /// `0.0.0.0/0` and `::/0`, mirroring the behavior of `start_server`.
+    #[pyo3(signature = (public_key, allowed_ips, preshared_key=None))]
+    pub fn add_peer(
+        &self,
+        public_key: String,
+        allowed_ips: Vec<(String, u8)>,
+        preshared_key: Option<Vec<u8>>,
+    ) -> PyResult<()> {
+        let public_key = string_to_key(public_key)?;
+        let allowed_ips = allowed_ips
+            .into_iter()
+            .map(|(addr, prefix_len)| {
+                addr.parse::<IpAddr>()
+                    .map(|addr| (addr, prefix_len))
+                    .map_err(|err| PyValueError::new_err(err.to_string()))
+            })
+            .collect::<PyResult<Vec<(IpAddr, u8)>>>()?;
+        let allowed_ips = catch_all_if_empty(allowed_ips);
+        let preshared_key = preshared_key
+            .map(|key| {
+                key.try_into()
+                    .map_err(|_| PyValueError::new_err("preshared_key must be exactly 32 bytes"))
+            })
+            .transpose()?;
+
+        self.server.update_peers(PeerUpdate::Add {
+            public_key,
+            allowed_ips,
+            preshared_key,
+        })
+    }
+
+    /// Remove a WireGuard peer from the already-running server, dropping any session state
+    /// associated with it.
+    pub fn remove_peer(&self, public_key: String) -> PyResult<()> {
+        let public_key = string_to_key(public_key)?;
+        self.server.update_peers(PeerUpdate::Remove { public_key })
+    }
 }
 
 impl WireGuardServer {
@@ -284,42 +454,43 @@ impl WireGuardServer {
         port: u16,
         private_key: String,
         peer_public_keys: Vec<String>,
+        peer_allowed_ips: Vec<Vec<(String, u8)>>,
         py_tcp_handler: PyObject,
         py_udp_handler: PyObject,
     ) -> Result<Self> {
         let private_key = string_to_key(private_key)?;
 
-        let peer_public_keys = peer_public_keys
+        if peer_public_keys.len() != peer_allowed_ips.len() {
+            return Err(PyValueError::new_err(format!(
+                "peer_allowed_ips must have the same length as peer_public_keys (got {} and {})",
+                peer_allowed_ips.len(),
+                peer_public_keys.len()
+            ))
+            .into());
+        }
+
+        let peers = peer_public_keys
             .into_iter()
             .map(string_to_key)
-            .collect::<PyResult<Vec<PublicKey>>>()?;
-
-        // bind to UDP socket(s)
-        let socket_addrs = if host.is_empty() {
-            vec![
-                SocketAddr::new("0.0.0.0".parse().unwrap(), port),
-                SocketAddr::new("::".parse().unwrap(), port),
-            ]
-        } else {
-            vec![SocketAddr::new(host.parse()?, port)]
-        };
-
-        let socket = UdpSocket::bind(socket_addrs.as_slice()).await?;
+            .collect::<PyResult<Vec<PublicKey>>>()?
+            .into_iter()
+            .zip(peer_allowed_ips)
+            .map(|(key, allowed_ips)| {
+                let allowed_ips = allowed_ips
+                    .into_iter()
+                    .map(|(addr, prefix_len)| Ok((addr.parse::<IpAddr>()?, prefix_len)))
+                    .collect::<Result<Vec<(IpAddr, u8)>>>()?;
+                Ok((key, allowed_ips))
+            })
+            .collect::<Result<Vec<(PublicKey, Vec<(IpAddr, u8)>)>>>()?;
+
+        let socket = bind_dual_stack(&host, port, "WireGuard").await?;
         let local_addr = socket.local_addr()?;
 
-        log::debug!(
-            "WireGuard server listening for UDP connections on {} ...",
-            socket_addrs
-                .iter()
-                .map(|addr| addr.to_string())
-                .collect::<Vec<String>>()
-                .join(" and ")
-        );
-
         // initialize WireGuard server
         let mut wg_task_builder = WireGuardBuilder::new(socket, private_key);
-        for key in peer_public_keys {
-            wg_task_builder.add_peer(key, None)?;
+        for (key, allowed_ips) in peers {
+            wg_task_builder.add_peer(key, catch_all_if_empty(allowed_ips))?;
         }
 
         let server = Server::init(wg_task_builder, py_tcp_handler, py_udp_handler).await?;
@@ -327,6 +498,75 @@ impl WireGuardServer {
     }
 }
 
+/// A running QUIC tunnel server.
+///
+/// Carries intercepted IP packets as QUIC DATAGRAM frames, one datagram per packet, falling back
+/// to a length-prefixed reliable stream for packets exceeding the negotiated datagram size. Its
+/// public API mirrors `WireGuardServer`, since both feed the same `NetworkTask`/`PyInteropTask`
+/// pipeline; the only difference is the transport used to reach the client.
+#[pyclass]
+#[derive(Debug)]
+pub struct QuicServer {
+    /// local address of the QUIC UDP socket
+    local_addr: SocketAddr,
+    server: Server,
+}
+
+#[pymethods]
+impl QuicServer {
+    /// Send an individual UDP datagram using the specified source and destination addresses.
+    pub fn send_datagram(
+        &self,
+        data: Vec<u8>,
+        src_addr: &PyTuple,
+        dst_addr: &PyTuple,
+    ) -> PyResult<()> {
+        self.server.send_datagram(data, src_addr, dst_addr)
+    }
+
+    /// Request the QUIC server to gracefully shut down.
+    pub fn close(&mut self) {
+        self.server.close()
+    }
+
+    /// Wait until the QUIC server has shut down.
+    pub fn wait_closed<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        self.server.wait_closed(py)
+    }
+
+    /// Get the local socket address that the QUIC server is listening on.
+    pub fn getsockname(&self, py: Python) -> PyObject {
+        socketaddr_to_py(py, self.local_addr)
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!("QuicServer({})", self.local_addr)
+    }
+}
+
+impl QuicServer {
+    pub async fn init(
+        host: String,
+        port: u16,
+        tls_cert_pem: String,
+        tls_key_pem: String,
+        py_tcp_handler: PyObject,
+        py_udp_handler: PyObject,
+    ) -> Result<Self> {
+        let cert_chain = std::fs::read(&tls_cert_pem)
+            .map_err(|err| anyhow::anyhow!("failed to read TLS certificate {tls_cert_pem}: {err}"))?;
+        let key = std::fs::read(&tls_key_pem)
+            .map_err(|err| anyhow::anyhow!("failed to read TLS private key {tls_key_pem}: {err}"))?;
+
+        let socket = bind_dual_stack(&host, port, "QUIC").await?;
+        let local_addr = socket.local_addr()?;
+
+        let quic_task_builder = QuicBuilder::new(socket, cert_chain, key)?;
+
+        let server = Server::init(quic_task_builder, py_tcp_handler, py_udp_handler).await?;
+        Ok(QuicServer { local_addr, server })
+    }
+}
 
 /// Start a WireGuard server that is configured with the given parameters:
 ///
@@ -334,6 +574,9 @@ impl WireGuardServer {
 /// - `port`: The listen port for the WireGuard server. The default port for WireGuard is `51820`.
 /// - `private_key`: The private X25519 key for the WireGuard server as a base64-encoded string.
 /// - `peer_public_keys`: List of public X25519 keys for WireGuard peers as base64-encoded strings.
+/// - `peer_allowed_ips`: For each entry in `peer_public_keys`, the list of `(ip, prefix_len)`
+///   allowed-IPs ranges that peer is permitted to send from and receive traffic for. An empty
+///   list defaults to `0.0.0.0/0` and `::/0`, i.e. that peer receives all outbound traffic.
 /// - `handle_connection`: A coroutine that will be called for each new `TcpStream`.
 /// - `receive_datagram`: A function that will be called for each received UDP datagram.
 ///
@@ -349,6 +592,7 @@ pub fn start_server(
     port: u16,
     private_key: String,
     peer_public_keys: Vec<String>,
+    peer_allowed_ips: Vec<Vec<(String, u8)>>,
     handle_connection: PyObject,
     receive_datagram: PyObject,
 ) -> PyResult<&PyAny> {
@@ -358,6 +602,7 @@ pub fn start_server(
             port,
             private_key,
             peer_public_keys,
+            peer_allowed_ips,
             handle_connection,
             receive_datagram,
         )
@@ -366,6 +611,7 @@ pub fn start_server(
     })
 }
 
+#[cfg(windows)]
 #[pyfunction]
 pub fn start_windows_transparent_proxy(
     py: Python<'_>,
@@ -377,3 +623,84 @@ pub fn start_windows_transparent_proxy(
         Ok(server)
     })
 }
+
+/// Start a transparent proxy fed by a local redirector through a Unix domain socket.
+///
+/// The redirector (e.g. an nfqueue or pf/divert-backed helper process) connects to the socket at
+/// `/tmp/mitmproxy-<pid>.sock` and exchanges intercepted IP packets using the same length-prefixed
+/// framing as the Windows named-pipe transport: each message is a 2-byte big-endian length prefix
+/// followed by that many bytes of packet data.
+#[cfg(unix)]
+#[pyfunction]
+pub fn start_unix_transparent_proxy(
+    py: Python<'_>,
+    handle_connection: PyObject,
+    receive_datagram: PyObject,
+) -> PyResult<&PyAny> {
+    pyo3_asyncio::tokio::future_into_py(py, async move {
+        let server = UnixProxy::init(handle_connection, receive_datagram).await?;
+        Ok(server)
+    })
+}
+
+/// Stub for platforms without a WinDivert-based transparent proxy implementation.
+#[cfg(not(windows))]
+#[pyfunction]
+pub fn start_windows_transparent_proxy(
+    _py: Python<'_>,
+    _handle_connection: PyObject,
+    _receive_datagram: PyObject,
+) -> PyResult<&PyAny> {
+    Err(pyo3::exceptions::PyNotImplementedError::new_err(
+        "start_windows_transparent_proxy is only available on Windows",
+    ))
+}
+
+/// Stub for platforms without a Unix-domain-socket transparent proxy implementation.
+#[cfg(not(unix))]
+#[pyfunction]
+pub fn start_unix_transparent_proxy(
+    _py: Python<'_>,
+    _handle_connection: PyObject,
+    _receive_datagram: PyObject,
+) -> PyResult<&PyAny> {
+    Err(pyo3::exceptions::PyNotImplementedError::new_err(
+        "start_unix_transparent_proxy is only available on Linux and macOS",
+    ))
+}
+
+/// Start a QUIC server that is configured with the given parameters:
+///
+/// - `host`: The host address for the QUIC UDP socket.
+/// - `port`: The listen port for the QUIC server.
+/// - `tls_cert_pem`: Path to a PEM-encoded TLS certificate (chain) presented to connecting clients.
+/// - `tls_key_pem`: Path to the PEM-encoded TLS private key matching `tls_cert_pem`.
+/// - `handle_connection`: A coroutine that will be called for each new `TcpStream`.
+/// - `receive_datagram`: A function that will be called for each received UDP datagram.
+///
+/// This complements `start_server`: clients behind middleboxes that block raw WireGuard UDP can
+/// instead connect over QUIC, which looks like ordinary UDP/HTTP3 traffic, while feeding the same
+/// `NetworkTask`/Python-interop pipeline.
+#[pyfunction]
+pub fn start_quic_server(
+    py: Python<'_>,
+    host: String,
+    port: u16,
+    tls_cert_pem: String,
+    tls_key_pem: String,
+    handle_connection: PyObject,
+    receive_datagram: PyObject,
+) -> PyResult<&PyAny> {
+    pyo3_asyncio::tokio::future_into_py(py, async move {
+        let server = QuicServer::init(
+            host,
+            port,
+            tls_cert_pem,
+            tls_key_pem,
+            handle_connection,
+            receive_datagram,
+        )
+        .await?;
+        Ok(server)
+    })
+}